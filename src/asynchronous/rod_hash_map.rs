@@ -1,44 +1,278 @@
 use std::{
     borrow::Borrow,
     collections::HashSet,
-    hash::Hash,
+    hash::{BuildHasher, Hash, RandomState},
     ops::Deref,
-    sync::{Arc, Weak},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    thread::available_parallelism,
 };
 
-use async_std::sync::RwLock;
+use super::runtime;
+use runtime::RwLock;
+
+/// Number of shards to split the inner set into, sized so that disjoint keys
+/// hashing to different shards can be accessed without contending on the
+/// same lock.
+fn shard_count() -> usize {
+    let parallelism = available_parallelism().map_or(1, |n| n.get());
+
+    (parallelism * 4).next_power_of_two()
+}
+
+type Shards<K, V> = Arc<[RwLock<HashSet<RodEntry<K, V>>>]>;
+
+/// Index of the shard owning `hash`, taken from its high bits so that
+/// adjacent hashes still spread across shards.
+fn shard_index(shard_count: usize, hash: u64) -> usize {
+    (hash >> (u64::BITS - shard_count.trailing_zeros())) as usize
+}
+
+/// A key and generation queued for removal once the shard it lives in can
+/// be locked without blocking the dropping task.
+struct CleanupMessage<K> {
+    hash: u64,
+    generation: u64,
+    key: Arc<K>,
+}
+
+async fn run_cleanup_task<K, V>(
+    shards: Shards<K, V>,
+    mut receiver: runtime::Receiver<CleanupMessage<K>>,
+    pending: Arc<AtomicUsize>,
+) where
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    while let Some(message) = runtime::recv(&mut receiver).await {
+        let index = shard_index(shards.len(), message.hash);
+        let mut shard = shards[index].write().await;
+
+        // The entry for this key may have been removed and a new one
+        // reinserted since the guard that queued this message expired;
+        // only remove it if it is still the entry that guard owned.
+        if shard
+            .get(&*message.key)
+            .is_some_and(|entry| entry.generation == message.generation)
+        {
+            shard.remove(&*message.key);
+        }
+
+        drop(shard);
+        pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
 
 pub struct RodHashMap<K, V>
 where
-    K: Eq + Hash,
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
 {
-    inner: Arc<RwLock<HashSet<RodEntry<K, V>>>>,
+    shards: Shards<K, V>,
+    hasher: RandomState,
+    generation: AtomicU64,
+    cleanup_tx: runtime::Sender<CleanupMessage<K>>,
+    /// Number of `RodGuard::drop`s whose removal hasn't been applied by
+    /// the background cleanup task yet; `len()` may transiently overcount
+    /// by this much until [`Self::flush_pending`] is awaited.
+    pending: Arc<AtomicUsize>,
 }
 
-impl<K: Eq + Hash, V> RodHashMap<K, V> {
+impl<K, V> RodHashMap<K, V>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
     pub fn new() -> Self {
+        let shards: Shards<K, V> = (0..shard_count())
+            .map(|_| RwLock::new(HashSet::new()))
+            .collect::<Vec<_>>()
+            .into();
+        let (cleanup_tx, cleanup_rx) = runtime::channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        runtime::spawn(run_cleanup_task(
+            Arc::clone(&shards),
+            cleanup_rx,
+            Arc::clone(&pending),
+        ));
+
         Self {
-            inner: Arc::new(RwLock::new(HashSet::new())),
+            shards,
+            hasher: RandomState::new(),
+            generation: AtomicU64::new(0),
+            cleanup_tx,
+            pending,
         }
     }
 
+    /// Next generation to tag a freshly inserted entry with, so a guard
+    /// whose `Drop` races a reinsertion under the same key can tell its
+    /// entry apart from the one that replaced it.
+    fn next_generation(&self) -> u64 {
+        self.generation.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Number of entries currently alive. May transiently overcount by
+    /// the number of `RodGuard::drop`s whose removal hasn't been applied
+    /// by the background cleanup task yet; await [`Self::flush_pending`]
+    /// for an exact count.
     pub async fn len(&self) -> usize {
-        self.inner.read().await.len()
+        let mut len = 0;
+
+        for shard in self.shards.iter() {
+            len += shard.read().await.len();
+        }
+
+        len
     }
 
+    /// Whether the map has no live entries. Subject to the same
+    /// transient overcount as [`Self::len`].
     pub async fn is_empty(&self) -> bool {
-        self.inner.read().await.is_empty()
+        self.len().await == 0
+    }
+
+    /// Waits until every `RodGuard::drop` queued so far has had its
+    /// removal applied, so that `len()`/`is_empty()` reflect them. Mainly
+    /// useful in tests and shutdown paths that need deterministic
+    /// reclamation.
+    pub async fn flush_pending(&self) {
+        while self.pending.load(Ordering::Acquire) > 0 {
+            runtime::yield_now().await;
+        }
     }
 
-    pub async fn insert(&mut self, key: K, value: V) -> Arc<RodGuard<K, V>> {
-        let (entry, guard) = RodEntry::new(Arc::clone(&self.inner), key, value);
-        self.inner.write().await.insert(entry);
+    pub async fn insert(&self, key: K, value: V) -> Arc<RodGuard<K, V>> {
+        let hash = self.hasher.hash_one(&key);
+        let index = shard_index(self.shards.len(), hash);
+        let generation = self.next_generation();
+        let (entry, guard) = RodEntry::new(
+            hash,
+            generation,
+            self.cleanup_tx.clone(),
+            Arc::clone(&self.pending),
+            key,
+            value,
+        );
+        self.shards[index].write().await.insert(entry);
 
         guard
     }
 
     pub async fn get(&self, key: &K) -> Option<Arc<RodGuard<K, V>>> {
-        self.inner.read().await.get(key).map(|entry| entry.get())
+        let hash = self.hasher.hash_one(key);
+        let index = shard_index(self.shards.len(), hash);
+
+        self.shards[index]
+            .read()
+            .await
+            .get(key)
+            .and_then(|entry| entry.value.upgrade())
+    }
+
+    /// Returns a weak handle to `key`'s entry without extending its
+    /// lifetime, so holding it doesn't keep the entry alive the way a
+    /// [`RodGuard`] would.
+    pub async fn get_weak(&self, key: &K) -> Option<RodWeak<K, V>> {
+        let hash = self.hasher.hash_one(key);
+        let index = shard_index(self.shards.len(), hash);
+
+        self.shards[index]
+            .read()
+            .await
+            .get(key)
+            .map(|entry| RodWeak {
+                inner: entry.value.clone(),
+            })
+    }
+
+    /// Atomically looks up `key`, returning its guard if a live entry
+    /// exists, or calls `f` and inserts the result otherwise.
+    ///
+    /// Unlike a separate `get()` followed by `insert()`, this takes the
+    /// shard's write lock once for the whole operation, so two callers can
+    /// never both miss and insert a duplicate entry for the same key.
+    pub async fn get_or_insert_with(
+        &self,
+        key: K,
+        f: impl FnOnce() -> V,
+    ) -> Arc<RodGuard<K, V>> {
+        let hash = self.hasher.hash_one(&key);
+        let index = shard_index(self.shards.len(), hash);
+        let mut shard = self.shards[index].write().await;
+
+        if let Some(guard) = shard.get(&key).and_then(|entry| entry.value.upgrade()) {
+            return guard;
+        }
+
+        let generation = self.next_generation();
+        let (entry, guard) = RodEntry::new(
+            hash,
+            generation,
+            self.cleanup_tx.clone(),
+            Arc::clone(&self.pending),
+            key,
+            f(),
+        );
+        shard.replace(entry);
+
+        guard
+    }
+
+    /// Returns a guard for every entry currently alive, in no particular
+    /// order. An entry whose `Weak` fails to upgrade (its guard is being
+    /// dropped concurrently) is skipped rather than included as a gap.
+    pub async fn iter(&self) -> Vec<Arc<RodGuard<K, V>>> {
+        let mut guards = Vec::new();
+
+        for shard in self.shards.iter() {
+            guards.extend(shard.read().await.iter().filter_map(|entry| entry.value.upgrade()));
+        }
+
+        guards
+    }
+
+    /// Calls `f` with every live key/guard pair, in no particular order.
+    pub async fn for_each(&self, mut f: impl FnMut(&K, &Arc<RodGuard<K, V>>)) {
+        for shard in self.shards.iter() {
+            let entries: Vec<_> = shard
+                .read()
+                .await
+                .iter()
+                .filter_map(|entry| entry.value.upgrade().map(|guard| (Arc::clone(&entry.key), guard)))
+                .collect();
+
+            for (key, guard) in &entries {
+                f(key, guard);
+            }
+        }
+    }
+
+    /// Keeps only the live entries for which `f` returns `true`. The rest
+    /// are removed from the map at once; guards the caller still holds for
+    /// them stay valid, but the map no longer finds them.
+    pub async fn retain(&self, mut f: impl FnMut(&K, &V) -> bool) {
+        for shard in self.shards.iter() {
+            shard.write().await.retain(|entry| {
+                entry
+                    .value
+                    .upgrade()
+                    .is_some_and(|guard| f(&entry.key, &guard))
+            });
+        }
+    }
+}
+
+impl<K, V> Default for RodHashMap<K, V>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -47,17 +281,34 @@ where
     K: Eq + Hash,
 {
     key: Arc<K>,
+    generation: u64,
     value: Weak<RodGuard<K, V>>,
 }
 
 impl<K: Eq + Hash, V> RodEntry<K, V> {
-    fn new(parent: Arc<RwLock<HashSet<Self>>>, key: K, value: V) -> (Self, Arc<RodGuard<K, V>>) {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        hash: u64,
+        generation: u64,
+        cleanup_tx: runtime::Sender<CleanupMessage<K>>,
+        pending: Arc<AtomicUsize>,
+        key: K,
+        value: V,
+    ) -> (Self, Arc<RodGuard<K, V>>) {
         let key = Arc::new(key);
-        let guard = Arc::new(RodGuard::new(parent, Arc::clone(&key), value));
+        let guard = Arc::new(RodGuard::new(
+            hash,
+            generation,
+            cleanup_tx,
+            pending,
+            Arc::clone(&key),
+            value,
+        ));
 
         (
             Self {
                 key,
+                generation,
                 value: Arc::downgrade(&guard),
             },
             guard,
@@ -95,14 +346,39 @@ pub struct RodGuard<K, V>
 where
     K: Eq + Hash,
 {
-    parent: Arc<RwLock<HashSet<RodEntry<K, V>>>>,
+    hash: u64,
+    generation: u64,
+    cleanup_tx: runtime::Sender<CleanupMessage<K>>,
+    pending: Arc<AtomicUsize>,
     key: Arc<K>,
     value: V,
 }
 
 impl<K: Eq + Hash, V> RodGuard<K, V> {
-    fn new(parent: Arc<RwLock<HashSet<RodEntry<K, V>>>>, key: Arc<K>, value: V) -> Self {
-        Self { parent, key, value }
+    fn new(
+        hash: u64,
+        generation: u64,
+        cleanup_tx: runtime::Sender<CleanupMessage<K>>,
+        pending: Arc<AtomicUsize>,
+        key: Arc<K>,
+        value: V,
+    ) -> Self {
+        Self {
+            hash,
+            generation,
+            cleanup_tx,
+            pending,
+            key,
+            value,
+        }
+    }
+
+    /// Returns a weak handle to this guard's entry that, unlike the guard
+    /// itself, does not keep the entry alive in the map.
+    pub fn downgrade(this: &Arc<Self>) -> RodWeak<K, V> {
+        RodWeak {
+            inner: Arc::downgrade(this),
+        }
     }
 }
 
@@ -116,20 +392,58 @@ impl<K: Eq + Hash, V> Deref for RodGuard<K, V> {
 
 impl<K: Eq + Hash, V> Drop for RodGuard<K, V> {
     fn drop(&mut self) {
-        async_std::task::block_on(self.parent.write()).remove(&*self.key);
+        // Stays synchronous and non-blocking: the actual removal happens
+        // on the background cleanup task, so dropping a guard can never
+        // block on (or deadlock with) another task holding the shard lock.
+        self.pending.fetch_add(1, Ordering::AcqRel);
+
+        runtime::push(
+            &self.cleanup_tx,
+            CleanupMessage {
+                hash: self.hash,
+                generation: self.generation,
+                key: Arc::clone(&self.key),
+            },
+        );
+    }
+}
+
+/// A non-owning handle to a [`RodGuard`], obtained via [`RodGuard::downgrade`]
+/// or [`RodHashMap::get_weak`]. Upgrading it does not prevent the entry from
+/// being removed once every [`Arc<RodGuard>`] referencing it is dropped.
+pub struct RodWeak<K, V>
+where
+    K: Eq + Hash,
+{
+    inner: Weak<RodGuard<K, V>>,
+}
+
+impl<K: Eq + Hash, V> RodWeak<K, V> {
+    pub fn upgrade(&self) -> Option<Arc<RodGuard<K, V>>> {
+        self.inner.upgrade()
+    }
+}
+
+impl<K: Eq + Hash, V> Clone for RodWeak<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::RodHashMap;
+    use std::sync::Arc;
+
+    use super::{runtime, RodHashMap};
 
     #[test]
     fn single_guard() {
-        async_std::task::block_on(async {
+        runtime::block_on(async {
             struct Room;
 
-            let mut hotel = RodHashMap::<&str, Room>::new();
+            let hotel = RodHashMap::<&str, Room>::new();
 
             assert!(hotel.is_empty().await);
 
@@ -138,6 +452,7 @@ mod tests {
             assert_eq!(hotel.len().await, 1);
 
             drop(room_0);
+            hotel.flush_pending().await;
 
             assert!(hotel.is_empty().await);
         });
@@ -145,10 +460,10 @@ mod tests {
 
     #[test]
     fn cloned_guard() {
-        async_std::task::block_on(async {
+        runtime::block_on(async {
             struct Room;
 
-            let mut hotel = RodHashMap::<&str, Room>::new();
+            let hotel = RodHashMap::<&str, Room>::new();
 
             assert!(hotel.is_empty().await);
 
@@ -158,10 +473,12 @@ mod tests {
             assert_eq!(hotel.len().await, 1);
 
             drop(room_0);
+            hotel.flush_pending().await;
 
             assert_eq!(hotel.len().await, 1);
 
             drop(room_0_clone);
+            hotel.flush_pending().await;
 
             assert!(hotel.is_empty().await);
         });
@@ -169,10 +486,10 @@ mod tests {
 
     #[test]
     fn insert_and_get() {
-        async_std::task::block_on(async {
+        runtime::block_on(async {
             struct Room;
 
-            let mut hotel = RodHashMap::<&str, Room>::new();
+            let hotel = RodHashMap::<&str, Room>::new();
 
             assert!(hotel.is_empty().await);
 
@@ -183,12 +500,185 @@ mod tests {
             assert_eq!(hotel.len().await, 1);
 
             drop(room_0_from_insert);
+            hotel.flush_pending().await;
 
             assert_eq!(hotel.len().await, 1);
 
             drop(room_0_from_get);
+            hotel.flush_pending().await;
+
+            assert!(hotel.is_empty().await);
+        });
+    }
+
+    #[test]
+    fn get_or_insert_with_returns_existing_guard() {
+        runtime::block_on(async {
+            let hotel = RodHashMap::<&str, u32>::new();
+
+            let room_0 = hotel.insert("Room Number 0", 0).await;
+            let room_0_again = hotel
+                .get_or_insert_with("Room Number 0", || panic!("must not run"))
+                .await;
+
+            assert!(Arc::ptr_eq(&room_0, &room_0_again));
+            assert_eq!(hotel.len().await, 1);
+        });
+    }
+
+    #[test]
+    fn get_or_insert_with_inserts_when_missing() {
+        runtime::block_on(async {
+            let hotel = RodHashMap::<&str, u32>::new();
+
+            assert!(hotel.is_empty().await);
+
+            let room_0 = hotel.get_or_insert_with("Room Number 0", || 42).await;
+
+            assert_eq!(**room_0, 42);
+            assert_eq!(hotel.len().await, 1);
+        });
+    }
+
+    #[test]
+    fn len_counts_entries_across_every_shard() {
+        runtime::block_on(async {
+            let hotel = RodHashMap::<u32, u32>::new();
+
+            let mut guards = Vec::new();
+            for i in 0..64 {
+                guards.push(hotel.insert(i, i).await);
+            }
+
+            assert_eq!(hotel.len().await, 64);
+
+            drop(guards);
+            hotel.flush_pending().await;
+
+            assert!(hotel.is_empty().await);
+        });
+    }
+
+    #[test]
+    fn dropping_a_displaced_guard_does_not_evict_the_live_entry() {
+        runtime::block_on(async {
+            let hotel = RodHashMap::<&str, u32>::new();
+
+            let room_0 = hotel.insert("Room Number 0", 0).await;
+            // `HashSet::insert` does not replace an equal element, so this
+            // second insert for the same key is silently discarded from the
+            // set while still handing back a live, orphaned guard.
+            let room_0_displaced = hotel.insert("Room Number 0", 1).await;
+
+            assert_eq!(hotel.len().await, 1);
+
+            // Dropping the displaced guard must not evict `room_0`'s
+            // entry: its generation no longer matches what is stored.
+            drop(room_0_displaced);
+            hotel.flush_pending().await;
+
+            assert_eq!(hotel.len().await, 1);
+            assert_eq!(**room_0, 0);
+        });
+    }
+
+    #[test]
+    fn iter_yields_every_live_guard() {
+        runtime::block_on(async {
+            let hotel = RodHashMap::<u32, u32>::new();
+
+            let mut _guards = Vec::new();
+            for i in 0..8 {
+                _guards.push(hotel.insert(i, i * 10).await);
+            }
+
+            let mut values: Vec<_> = hotel.iter().await.into_iter().map(|guard| **guard).collect();
+            values.sort_unstable();
+
+            assert_eq!(values, (0..8).map(|i| i * 10).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn for_each_visits_every_live_entry() {
+        runtime::block_on(async {
+            let hotel = RodHashMap::<u32, u32>::new();
+
+            let mut _guards = Vec::new();
+            for i in 0..8 {
+                _guards.push(hotel.insert(i, i * 10).await);
+            }
+
+            let mut seen = Vec::new();
+            hotel.for_each(|key, guard| seen.push((*key, ***guard))).await;
+            seen.sort_unstable();
+
+            assert_eq!(seen, (0..8).map(|i| (i, i * 10)).collect::<Vec<_>>());
+        });
+    }
+
+    #[test]
+    fn retain_drops_the_map_side_of_non_matching_entries() {
+        runtime::block_on(async {
+            let hotel = RodHashMap::<u32, u32>::new();
+
+            let mut guards = Vec::new();
+            for i in 0..8 {
+                guards.push(hotel.insert(i, i).await);
+            }
+
+            hotel.retain(|key, _| key % 2 == 0).await;
+
+            assert_eq!(hotel.len().await, 4);
+
+            drop(guards);
+            hotel.flush_pending().await;
+
+            assert!(hotel.is_empty().await);
+        });
+    }
+
+    #[test]
+    fn weak_upgrades_while_the_entry_is_alive() {
+        runtime::block_on(async {
+            let hotel = RodHashMap::<&str, u32>::new();
+
+            let room_0 = hotel.insert("Room Number 0", 0).await;
+            let weak = super::RodGuard::downgrade(&room_0);
+
+            let upgraded = weak.upgrade().unwrap();
+
+            assert_eq!(**upgraded, 0);
+        });
+    }
+
+    #[test]
+    fn weak_fails_to_upgrade_once_the_entry_is_gone() {
+        runtime::block_on(async {
+            let hotel = RodHashMap::<&str, u32>::new();
+
+            let room_0 = hotel.insert("Room Number 0", 0).await;
+            let weak = super::RodGuard::downgrade(&room_0);
+
+            drop(room_0);
+
+            assert!(weak.upgrade().is_none());
+        });
+    }
+
+    #[test]
+    fn get_weak_does_not_keep_the_entry_alive() {
+        runtime::block_on(async {
+            let hotel = RodHashMap::<&str, u32>::new();
+
+            let room_0 = hotel.insert("Room Number 0", 0).await;
+            let weak = hotel.get_weak(&"Room Number 0").await.unwrap();
+
+            drop(room_0);
+            hotel.flush_pending().await;
 
             assert!(hotel.is_empty().await);
+            assert!(weak.upgrade().is_none());
         });
     }
 }
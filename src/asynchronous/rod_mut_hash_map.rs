@@ -0,0 +1,475 @@
+use std::{
+    borrow::Borrow,
+    collections::HashSet,
+    hash::{BuildHasher, Hash, RandomState},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    thread::available_parallelism,
+};
+
+use super::runtime;
+use runtime::RwLock;
+
+/// Number of shards to split the inner set into, sized so that disjoint keys
+/// hashing to different shards can be accessed without contending on the
+/// same lock.
+fn shard_count() -> usize {
+    let parallelism = available_parallelism().map_or(1, |n| n.get());
+
+    (parallelism * 4).next_power_of_two()
+}
+
+type Shards<K, V> = Arc<[RwLock<HashSet<RodEntry<K, V>>>]>;
+
+/// Index of the shard owning `hash`, taken from its high bits so that
+/// adjacent hashes still spread across shards.
+fn shard_index(shard_count: usize, hash: u64) -> usize {
+    (hash >> (u64::BITS - shard_count.trailing_zeros())) as usize
+}
+
+/// A key and generation queued for removal once the shard it lives in can
+/// be locked without blocking the dropping task.
+struct CleanupMessage<K> {
+    hash: u64,
+    generation: u64,
+    key: Arc<K>,
+}
+
+async fn run_cleanup_task<K, V>(
+    shards: Shards<K, V>,
+    mut receiver: runtime::Receiver<CleanupMessage<K>>,
+    pending: Arc<AtomicUsize>,
+) where
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    while let Some(message) = runtime::recv(&mut receiver).await {
+        let index = shard_index(shards.len(), message.hash);
+        let mut shard = shards[index].write().await;
+
+        // The entry for this key may have been removed and a new one
+        // reinserted since the guard that queued this message expired;
+        // only remove it if it is still the entry that guard owned.
+        if shard
+            .get(&*message.key)
+            .is_some_and(|entry| entry.generation == message.generation)
+        {
+            shard.remove(&*message.key);
+        }
+
+        drop(shard);
+        pending.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Like [`super::rod_hash_map::RodHashMap`], but each entry's value lives
+/// behind its own `RwLock` so callers can mutate a shared entry in place
+/// via [`RodGuard::get_mut`] instead of replacing the whole value.
+pub struct RodMutHashMap<K, V>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    shards: Shards<K, V>,
+    hasher: RandomState,
+    generation: AtomicU64,
+    cleanup_tx: runtime::Sender<CleanupMessage<K>>,
+    /// Number of `RodGuard::drop`s whose removal hasn't been applied by
+    /// the background cleanup task yet; `len()` may transiently overcount
+    /// by this much until [`Self::flush_pending`] is awaited.
+    pending: Arc<AtomicUsize>,
+}
+
+impl<K, V> RodMutHashMap<K, V>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    pub fn new() -> Self {
+        let shards: Shards<K, V> = (0..shard_count())
+            .map(|_| RwLock::new(HashSet::new()))
+            .collect::<Vec<_>>()
+            .into();
+        let (cleanup_tx, cleanup_rx) = runtime::channel();
+        let pending = Arc::new(AtomicUsize::new(0));
+
+        runtime::spawn(run_cleanup_task(
+            Arc::clone(&shards),
+            cleanup_rx,
+            Arc::clone(&pending),
+        ));
+
+        Self {
+            shards,
+            hasher: RandomState::new(),
+            generation: AtomicU64::new(0),
+            cleanup_tx,
+            pending,
+        }
+    }
+
+    /// Next generation to tag a freshly inserted entry with, so a guard
+    /// whose `Drop` races a reinsertion under the same key can tell its
+    /// entry apart from the one that replaced it.
+    fn next_generation(&self) -> u64 {
+        self.generation.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Number of entries currently alive. May transiently overcount by
+    /// the number of `RodGuard::drop`s whose removal hasn't been applied
+    /// by the background cleanup task yet; await [`Self::flush_pending`]
+    /// for an exact count.
+    pub async fn len(&self) -> usize {
+        let mut len = 0;
+
+        for shard in self.shards.iter() {
+            len += shard.read().await.len();
+        }
+
+        len
+    }
+
+    /// Whether the map has no live entries. Subject to the same
+    /// transient overcount as [`Self::len`].
+    pub async fn is_empty(&self) -> bool {
+        self.len().await == 0
+    }
+
+    /// Waits until every `RodGuard::drop` queued so far has had its
+    /// removal applied, so that `len()`/`is_empty()` reflect them. Mainly
+    /// useful in tests and shutdown paths that need deterministic
+    /// reclamation.
+    pub async fn flush_pending(&self) {
+        while self.pending.load(Ordering::Acquire) > 0 {
+            runtime::yield_now().await;
+        }
+    }
+
+    pub async fn insert(&self, key: K, value: V) -> Arc<RodGuard<K, V>> {
+        let hash = self.hasher.hash_one(&key);
+        let index = shard_index(self.shards.len(), hash);
+        let generation = self.next_generation();
+        let (entry, guard) = RodEntry::new(
+            hash,
+            generation,
+            self.cleanup_tx.clone(),
+            Arc::clone(&self.pending),
+            key,
+            value,
+        );
+        self.shards[index].write().await.insert(entry);
+
+        guard
+    }
+
+    pub async fn get(&self, key: &K) -> Option<Arc<RodGuard<K, V>>> {
+        let hash = self.hasher.hash_one(key);
+        let index = shard_index(self.shards.len(), hash);
+
+        self.shards[index]
+            .read()
+            .await
+            .get(key)
+            .and_then(|entry| entry.get())
+    }
+
+    /// Atomically looks up `key`, returning its guard if a live entry
+    /// exists, or calls `f` and inserts the result otherwise.
+    ///
+    /// Unlike a separate `get()` followed by `insert()`, this takes the
+    /// shard's write lock once for the whole operation, so two callers can
+    /// never both miss and insert a duplicate entry for the same key.
+    pub async fn get_or_insert_with(
+        &self,
+        key: K,
+        f: impl FnOnce() -> V,
+    ) -> Arc<RodGuard<K, V>> {
+        let hash = self.hasher.hash_one(&key);
+        let index = shard_index(self.shards.len(), hash);
+        let mut shard = self.shards[index].write().await;
+
+        if let Some(guard) = shard.get(&key).and_then(|entry| entry.value.upgrade()) {
+            return guard;
+        }
+
+        let generation = self.next_generation();
+        let (entry, guard) = RodEntry::new(
+            hash,
+            generation,
+            self.cleanup_tx.clone(),
+            Arc::clone(&self.pending),
+            key,
+            f(),
+        );
+        shard.replace(entry);
+
+        guard
+    }
+}
+
+impl<K, V> Default for RodMutHashMap<K, V>
+where
+    K: Eq + Hash + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct RodEntry<K, V>
+where
+    K: Eq + Hash,
+{
+    key: Arc<K>,
+    generation: u64,
+    value: Weak<RodGuard<K, V>>,
+}
+
+impl<K: Eq + Hash, V> RodEntry<K, V> {
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        hash: u64,
+        generation: u64,
+        cleanup_tx: runtime::Sender<CleanupMessage<K>>,
+        pending: Arc<AtomicUsize>,
+        key: K,
+        value: V,
+    ) -> (Self, Arc<RodGuard<K, V>>) {
+        let key = Arc::new(key);
+        let guard = Arc::new(RodGuard::new(
+            hash,
+            generation,
+            cleanup_tx,
+            pending,
+            Arc::clone(&key),
+            value,
+        ));
+
+        (
+            Self {
+                key,
+                generation,
+                value: Arc::downgrade(&guard),
+            },
+            guard,
+        )
+    }
+
+    /// `None` once the guard is gone, even while its removal is still
+    /// queued for the background cleanup task.
+    fn get(&self) -> Option<Arc<RodGuard<K, V>>> {
+        self.value.upgrade()
+    }
+}
+
+impl<K: Eq + Hash, V> Borrow<K> for RodEntry<K, V> {
+    fn borrow(&self) -> &K {
+        &self.key
+    }
+}
+
+impl<K: Eq + Hash, V> PartialEq for RodEntry<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key.eq(&other.key)
+    }
+}
+
+impl<K: Eq + Hash, V> Eq for RodEntry<K, V> {}
+
+impl<K: Eq + Hash, V> Hash for RodEntry<K, V> {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key.hash(state);
+    }
+}
+
+/// A reference-counted, remove-on-drop handle whose value is locked
+/// independently of the map, so it can be mutated in place through any
+/// clone of the guard.
+///
+/// # Deadlock safety
+///
+/// This per-value lock is completely independent of the shard lock the map
+/// uses internally, and `Drop` never awaits it directly (removal is
+/// deferred to the background cleanup task, as with the plain async map).
+/// The one thing to avoid is holding a [`RodGuard::get_mut`]/
+/// [`RodGuard::read`] guard while trying to acquire *another* guard for the
+/// same entry on the same task — like any `RwLock`, that will deadlock a
+/// writer against itself.
+pub struct RodGuard<K, V>
+where
+    K: Eq + Hash,
+{
+    hash: u64,
+    generation: u64,
+    cleanup_tx: runtime::Sender<CleanupMessage<K>>,
+    pending: Arc<AtomicUsize>,
+    key: Arc<K>,
+    value: RwLock<V>,
+}
+
+impl<K: Eq + Hash, V> RodGuard<K, V> {
+    fn new(
+        hash: u64,
+        generation: u64,
+        cleanup_tx: runtime::Sender<CleanupMessage<K>>,
+        pending: Arc<AtomicUsize>,
+        key: Arc<K>,
+        value: V,
+    ) -> Self {
+        Self {
+            hash,
+            generation,
+            cleanup_tx,
+            pending,
+            key,
+            value: RwLock::new(value),
+        }
+    }
+
+    /// Acquires this entry's value for shared, read-only access.
+    pub async fn read(&self) -> runtime_types::RwLockReadGuard<'_, V> {
+        self.value.read().await
+    }
+
+    /// Acquires this entry's value for exclusive, mutable access.
+    pub async fn get_mut(&self) -> runtime_types::RwLockWriteGuard<'_, V> {
+        self.value.write().await
+    }
+}
+
+/// Re-exports the lock guard types returned by [`RodGuard::read`] and
+/// [`RodGuard::get_mut`], since `tokio` and `async-std` name them
+/// identically but aren't the same type.
+mod runtime_types {
+    #[cfg(feature = "tokio")]
+    pub(super) use tokio::sync::{RwLockReadGuard, RwLockWriteGuard};
+    #[cfg(feature = "async-std")]
+    pub(super) use async_std::sync::{RwLockReadGuard, RwLockWriteGuard};
+}
+
+impl<K: Eq + Hash, V> Drop for RodGuard<K, V> {
+    fn drop(&mut self) {
+        // Stays synchronous and non-blocking: the actual removal happens
+        // on the background cleanup task, so dropping a guard can never
+        // block on (or deadlock with) another task holding the shard lock.
+        self.pending.fetch_add(1, Ordering::AcqRel);
+
+        runtime::push(
+            &self.cleanup_tx,
+            CleanupMessage {
+                hash: self.hash,
+                generation: self.generation,
+                key: Arc::clone(&self.key),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{runtime, RodMutHashMap};
+
+    #[test]
+    fn single_guard() {
+        runtime::block_on(async {
+            let hotel = RodMutHashMap::<&str, u32>::new();
+
+            assert!(hotel.is_empty().await);
+
+            let room_0 = hotel.insert("Room Number 0", 0).await;
+
+            assert_eq!(hotel.len().await, 1);
+
+            drop(room_0);
+            hotel.flush_pending().await;
+
+            assert!(hotel.is_empty().await);
+        });
+    }
+
+    #[test]
+    fn get_mut_mutates_the_shared_entry() {
+        runtime::block_on(async {
+            let hotel = RodMutHashMap::<&str, u32>::new();
+
+            let room_0 = hotel.insert("Room Number 0", 0).await;
+            let room_0_again = hotel.get(&"Room Number 0").await.unwrap();
+
+            *room_0.get_mut().await += 1;
+
+            assert_eq!(*room_0_again.read().await, 1);
+        });
+    }
+
+    #[test]
+    fn get_or_insert_with_returns_existing_guard() {
+        runtime::block_on(async {
+            let hotel = RodMutHashMap::<&str, u32>::new();
+
+            let room_0 = hotel.insert("Room Number 0", 0).await;
+            let room_0_again = hotel
+                .get_or_insert_with("Room Number 0", || panic!("must not run"))
+                .await;
+
+            assert!(Arc::ptr_eq(&room_0, &room_0_again));
+            assert_eq!(hotel.len().await, 1);
+        });
+    }
+
+    #[test]
+    fn dropping_a_displaced_guard_does_not_evict_the_live_entry() {
+        runtime::block_on(async {
+            let hotel = RodMutHashMap::<&str, u32>::new();
+
+            let room_0 = hotel.insert("Room Number 0", 0).await;
+            // `HashSet::insert` does not replace an equal element, so this
+            // second insert for the same key is silently discarded from the
+            // set while still handing back a live, orphaned guard.
+            let room_0_displaced = hotel.insert("Room Number 0", 1).await;
+
+            assert_eq!(hotel.len().await, 1);
+
+            // Dropping the displaced guard must not evict `room_0`'s
+            // entry: its generation no longer matches what is stored.
+            drop(room_0_displaced);
+            hotel.flush_pending().await;
+
+            assert_eq!(hotel.len().await, 1);
+            assert_eq!(*room_0.read().await, 0);
+        });
+    }
+
+    #[test]
+    fn concurrent_inserts_of_disjoint_keys_succeed() {
+        runtime::block_on(async {
+            // Built inside the runtime so its cleanup task has one to run on.
+            let hotel = RodMutHashMap::<u32, u32>::new();
+
+            let guards = std::thread::scope(|scope| {
+                let handles: Vec<_> = (0..64)
+                    .map(|i| {
+                        let hotel = &hotel;
+                        scope.spawn(move || runtime::block_on(hotel.insert(i, i)))
+                    })
+                    .collect();
+
+                handles
+                    .into_iter()
+                    .map(|handle| handle.join().unwrap())
+                    .collect::<Vec<_>>()
+            });
+
+            assert_eq!(hotel.len().await, 64);
+
+            drop(guards);
+            hotel.flush_pending().await;
+
+            assert!(hotel.is_empty().await);
+        });
+    }
+}
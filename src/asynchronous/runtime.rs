@@ -0,0 +1,87 @@
+//! Thin, interchangeable async-runtime glue selected by the `tokio` /
+//! `async-std` cargo features, so the maps in this module don't hardcode one
+//! executor.
+
+#[cfg(all(feature = "tokio", feature = "async-std"))]
+compile_error!("enable only one of the `tokio` or `async-std` features");
+
+#[cfg(not(any(feature = "tokio", feature = "async-std")))]
+compile_error!("enable one of the `tokio` or `async-std` features");
+
+#[cfg(feature = "tokio")]
+pub(super) use tokio::sync::RwLock;
+#[cfg(feature = "async-std")]
+pub(super) use async_std::sync::RwLock;
+
+#[cfg(feature = "tokio")]
+pub(super) use tokio::sync::mpsc::{
+    unbounded_channel as channel, UnboundedReceiver as Receiver, UnboundedSender as Sender,
+};
+#[cfg(feature = "async-std")]
+pub(super) use async_std::channel::{unbounded as channel, Receiver, Sender};
+
+pub(super) fn spawn<F>(future: F)
+where
+    F: std::future::Future<Output = ()> + Send + 'static,
+{
+    #[cfg(feature = "tokio")]
+    {
+        tokio::spawn(future);
+    }
+    #[cfg(feature = "async-std")]
+    {
+        async_std::task::spawn(future);
+    }
+}
+
+pub(super) async fn recv<T>(receiver: &mut Receiver<T>) -> Option<T> {
+    #[cfg(feature = "tokio")]
+    {
+        receiver.recv().await
+    }
+    #[cfg(feature = "async-std")]
+    {
+        receiver.recv().await.ok()
+    }
+}
+
+/// Non-blocking, synchronous send used from `RodGuard::drop`.
+pub(super) fn push<T>(sender: &Sender<T>, item: T) {
+    #[cfg(feature = "tokio")]
+    {
+        let _ = sender.send(item);
+    }
+    #[cfg(feature = "async-std")]
+    {
+        let _ = sender.try_send(item);
+    }
+}
+
+pub(super) async fn yield_now() {
+    #[cfg(feature = "tokio")]
+    {
+        tokio::task::yield_now().await;
+    }
+    #[cfg(feature = "async-std")]
+    {
+        async_std::task::yield_now().await;
+    }
+}
+
+/// Runs `future` to completion on the selected runtime; used by tests,
+/// which must stay executor-agnostic just like the map itself.
+#[cfg(test)]
+pub(super) fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    #[cfg(feature = "tokio")]
+    {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("failed to start a current-thread tokio runtime")
+            .block_on(future)
+    }
+    #[cfg(feature = "async-std")]
+    {
+        async_std::task::block_on(future)
+    }
+}
@@ -1,42 +1,180 @@
 use std::{
     borrow::Borrow,
     collections::HashSet,
-    hash::Hash,
+    hash::{BuildHasher, Hash, RandomState},
     ops::Deref,
-    sync::{Arc, RwLock, Weak},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock, Weak,
+    },
+    thread::available_parallelism,
 };
 
+/// Number of shards to split the inner set into, sized so that disjoint keys
+/// hashing to different shards can be accessed without contending on the
+/// same lock.
+fn shard_count() -> usize {
+    let parallelism = available_parallelism().map_or(1, |n| n.get());
+
+    (parallelism * 4).next_power_of_two()
+}
+
+type Shards<K, V> = Arc<[RwLock<HashSet<RodEntry<K, V>>>]>;
+
+/// Index of the shard owning `hash`, taken from its high bits so that
+/// adjacent hashes still spread across shards.
+fn shard_index(shard_count: usize, hash: u64) -> usize {
+    (hash >> (u64::BITS - shard_count.trailing_zeros())) as usize
+}
+
 pub struct RodHashMap<K, V>
 where
     K: Eq + Hash,
 {
-    inner: Arc<RwLock<HashSet<RodEntry<K, V>>>>,
+    shards: Shards<K, V>,
+    hasher: RandomState,
+    generation: AtomicU64,
 }
 
 impl<K: Eq + Hash, V> RodHashMap<K, V> {
     pub fn new() -> Self {
+        let shards = (0..shard_count())
+            .map(|_| RwLock::new(HashSet::new()))
+            .collect::<Vec<_>>()
+            .into();
+
         Self {
-            inner: Arc::new(RwLock::new(HashSet::new())),
+            shards,
+            hasher: RandomState::new(),
+            generation: AtomicU64::new(0),
         }
     }
 
+    /// Next generation to tag a freshly inserted entry with, so a guard
+    /// whose `Drop` races a reinsertion under the same key can tell its
+    /// entry apart from the one that replaced it.
+    fn next_generation(&self) -> u64 {
+        self.generation.fetch_add(1, Ordering::Relaxed)
+    }
+
     pub fn len(&self) -> usize {
-        self.inner.read().unwrap().len()
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
     }
 
     pub fn is_empty(&self) -> bool {
-        self.inner.read().unwrap().is_empty()
+        self.len() == 0
     }
 
-    pub fn insert(&mut self, key: K, value: V) -> Arc<RodGuard<K, V>> {
-        let (entry, guard) = RodEntry::new(Arc::clone(&self.inner), key, value);
-        self.inner.write().unwrap().insert(entry);
+    pub fn insert(&self, key: K, value: V) -> Arc<RodGuard<K, V>> {
+        let hash = self.hasher.hash_one(&key);
+        let index = shard_index(self.shards.len(), hash);
+        let generation = self.next_generation();
+        let (entry, guard) = RodEntry::new(Arc::clone(&self.shards), hash, generation, key, value);
+        self.shards[index].write().unwrap().insert(entry);
 
         guard
     }
 
     pub fn get(&self, key: &K) -> Option<Arc<RodGuard<K, V>>> {
-        self.inner.read().unwrap().get(key).map(|entry| entry.get())
+        let hash = self.hasher.hash_one(key);
+        let index = shard_index(self.shards.len(), hash);
+
+        self.shards[index]
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|entry| entry.get())
+    }
+
+    /// Returns a weak handle to `key`'s entry without extending its
+    /// lifetime, so holding it doesn't keep the entry alive the way a
+    /// [`RodGuard`] would.
+    pub fn get_weak(&self, key: &K) -> Option<RodWeak<K, V>> {
+        let hash = self.hasher.hash_one(key);
+        let index = shard_index(self.shards.len(), hash);
+
+        self.shards[index]
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|entry| RodWeak {
+                inner: entry.value.clone(),
+            })
+    }
+
+    /// Atomically looks up `key`, returning its guard if a live entry
+    /// exists, or calls `f` and inserts the result otherwise.
+    ///
+    /// Unlike a separate `get()` followed by `insert()`, this takes the
+    /// shard's write lock once for the whole operation, so two callers can
+    /// never both miss and insert a duplicate entry for the same key.
+    pub fn get_or_insert_with(&self, key: K, f: impl FnOnce() -> V) -> Arc<RodGuard<K, V>> {
+        let hash = self.hasher.hash_one(&key);
+        let index = shard_index(self.shards.len(), hash);
+        let mut shard = self.shards[index].write().unwrap();
+
+        if let Some(guard) = shard.get(&key).and_then(|entry| entry.value.upgrade()) {
+            return guard;
+        }
+
+        let generation = self.next_generation();
+        let (entry, guard) = RodEntry::new(Arc::clone(&self.shards), hash, generation, key, f());
+        shard.replace(entry);
+
+        guard
+    }
+
+    /// Returns a guard for every entry currently alive, in no particular
+    /// order. An entry whose `Weak` fails to upgrade (its guard is being
+    /// dropped concurrently) is skipped rather than included as a gap.
+    pub fn iter(&self) -> Vec<Arc<RodGuard<K, V>>> {
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                shard
+                    .read()
+                    .unwrap()
+                    .iter()
+                    .filter_map(|entry| entry.value.upgrade())
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Calls `f` with every live key/guard pair, in no particular order.
+    pub fn for_each(&self, mut f: impl FnMut(&K, &Arc<RodGuard<K, V>>)) {
+        for shard in self.shards.iter() {
+            let entries: Vec<_> = shard
+                .read()
+                .unwrap()
+                .iter()
+                .filter_map(|entry| entry.value.upgrade().map(|guard| (Arc::clone(&entry.key), guard)))
+                .collect();
+
+            for (key, guard) in &entries {
+                f(key, guard);
+            }
+        }
+    }
+
+    /// Keeps only the live entries for which `f` returns `true`. The rest
+    /// are removed from the map at once; guards the caller still holds for
+    /// them stay valid, but the map no longer finds them.
+    pub fn retain(&self, mut f: impl FnMut(&K, &V) -> bool) {
+        for shard in self.shards.iter() {
+            shard.write().unwrap().retain(|entry| {
+                entry
+                    .value
+                    .upgrade()
+                    .is_some_and(|guard| f(&entry.key, &guard))
+            });
+        }
+    }
+}
+
+impl<K: Eq + Hash, V> Default for RodHashMap<K, V> {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
@@ -45,17 +183,31 @@ where
     K: Eq + Hash,
 {
     key: Arc<K>,
+    generation: u64,
     value: Weak<RodGuard<K, V>>,
 }
 
 impl<K: Eq + Hash, V> RodEntry<K, V> {
-    fn new(parent: Arc<RwLock<HashSet<Self>>>, key: K, value: V) -> (Self, Arc<RodGuard<K, V>>) {
+    fn new(
+        shards: Shards<K, V>,
+        hash: u64,
+        generation: u64,
+        key: K,
+        value: V,
+    ) -> (Self, Arc<RodGuard<K, V>>) {
         let key = Arc::new(key);
-        let guard = Arc::new(RodGuard::new(parent, Arc::clone(&key), value));
+        let guard = Arc::new(RodGuard::new(
+            shards,
+            hash,
+            generation,
+            Arc::clone(&key),
+            value,
+        ));
 
         (
             Self {
                 key,
+                generation,
                 value: Arc::downgrade(&guard),
             },
             guard,
@@ -93,14 +245,36 @@ pub struct RodGuard<K, V>
 where
     K: Eq + Hash,
 {
-    parent: Arc<RwLock<HashSet<RodEntry<K, V>>>>,
+    shards: Shards<K, V>,
+    hash: u64,
+    generation: u64,
     key: Arc<K>,
     value: V,
 }
 
 impl<K: Eq + Hash, V> RodGuard<K, V> {
-    fn new(parent: Arc<RwLock<HashSet<RodEntry<K, V>>>>, key: Arc<K>, value: V) -> Self {
-        Self { parent, key, value }
+    fn new(
+        shards: Shards<K, V>,
+        hash: u64,
+        generation: u64,
+        key: Arc<K>,
+        value: V,
+    ) -> Self {
+        Self {
+            shards,
+            hash,
+            generation,
+            key,
+            value,
+        }
+    }
+
+    /// Returns a weak handle to this guard's entry that, unlike the guard
+    /// itself, does not keep the entry alive in the map.
+    pub fn downgrade(this: &Arc<Self>) -> RodWeak<K, V> {
+        RodWeak {
+            inner: Arc::downgrade(this),
+        }
     }
 }
 
@@ -114,19 +288,56 @@ impl<K: Eq + Hash, V> Deref for RodGuard<K, V> {
 
 impl<K: Eq + Hash, V> Drop for RodGuard<K, V> {
     fn drop(&mut self) {
-        self.parent.write().unwrap().remove(&*self.key);
+        let index = shard_index(self.shards.len(), self.hash);
+        let mut shard = self.shards[index].write().unwrap();
+
+        // The entry for this key may have been removed and a new one
+        // reinserted since this guard's `Weak` expired; only remove it if
+        // it is still the entry we were created for.
+        if shard
+            .get(&*self.key)
+            .is_some_and(|entry| entry.generation == self.generation)
+        {
+            shard.remove(&*self.key);
+        }
+    }
+}
+
+/// A non-owning handle to a [`RodGuard`], obtained via [`RodGuard::downgrade`]
+/// or [`RodHashMap::get_weak`]. Upgrading it does not prevent the entry from
+/// being removed once every [`Arc<RodGuard>`] referencing it is dropped.
+pub struct RodWeak<K, V>
+where
+    K: Eq + Hash,
+{
+    inner: Weak<RodGuard<K, V>>,
+}
+
+impl<K: Eq + Hash, V> RodWeak<K, V> {
+    pub fn upgrade(&self) -> Option<Arc<RodGuard<K, V>>> {
+        self.inner.upgrade()
+    }
+}
+
+impl<K: Eq + Hash, V> Clone for RodWeak<K, V> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::sync::Arc;
+
     use super::RodHashMap;
 
     #[test]
     fn single_guard() {
         struct Room;
 
-        let mut hotel = RodHashMap::<&str, Room>::new();
+        let hotel = RodHashMap::<&str, Room>::new();
 
         assert!(hotel.is_empty());
 
@@ -143,7 +354,7 @@ mod tests {
     fn cloned_guard() {
         struct Room;
 
-        let mut hotel = RodHashMap::<&str, Room>::new();
+        let hotel = RodHashMap::<&str, Room>::new();
 
         assert!(hotel.is_empty());
 
@@ -165,7 +376,7 @@ mod tests {
     fn insert_and_get() {
         struct Room;
 
-        let mut hotel = RodHashMap::<&str, Room>::new();
+        let hotel = RodHashMap::<&str, Room>::new();
 
         assert!(hotel.is_empty());
 
@@ -183,4 +394,162 @@ mod tests {
 
         assert!(hotel.is_empty());
     }
+
+    #[test]
+    fn get_or_insert_with_returns_existing_guard() {
+        let hotel = RodHashMap::<&str, u32>::new();
+
+        let room_0 = hotel.insert("Room Number 0", 0);
+        let room_0_again = hotel.get_or_insert_with("Room Number 0", || panic!("must not run"));
+
+        assert!(Arc::ptr_eq(&room_0, &room_0_again));
+        assert_eq!(hotel.len(), 1);
+    }
+
+    #[test]
+    fn get_or_insert_with_inserts_when_missing() {
+        let hotel = RodHashMap::<&str, u32>::new();
+
+        assert!(hotel.is_empty());
+
+        let room_0 = hotel.get_or_insert_with("Room Number 0", || 42);
+
+        assert_eq!(**room_0, 42);
+        assert_eq!(hotel.len(), 1);
+    }
+
+    #[test]
+    fn len_counts_entries_across_every_shard() {
+        let hotel = RodHashMap::<u32, u32>::new();
+
+        let guards: Vec<_> = (0..64).map(|i| hotel.insert(i, i)).collect();
+
+        assert_eq!(hotel.len(), 64);
+
+        drop(guards);
+
+        assert!(hotel.is_empty());
+    }
+
+    #[test]
+    fn concurrent_inserts_of_disjoint_keys_succeed() {
+        let hotel = RodHashMap::<u32, u32>::new();
+
+        let guards = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..64)
+                .map(|i| {
+                    let hotel = &hotel;
+                    scope.spawn(move || hotel.insert(i, i))
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        assert_eq!(hotel.len(), 64);
+
+        drop(guards);
+
+        assert!(hotel.is_empty());
+    }
+
+    #[test]
+    fn dropping_a_displaced_guard_does_not_evict_the_live_entry() {
+        let hotel = RodHashMap::<&str, u32>::new();
+
+        let room_0 = hotel.insert("Room Number 0", 0);
+        // `HashSet::insert` does not replace an equal element, so this
+        // second insert for the same key is silently discarded from the
+        // set while still handing back a live, orphaned guard.
+        let room_0_displaced = hotel.insert("Room Number 0", 1);
+
+        assert_eq!(hotel.len(), 1);
+
+        // Dropping the displaced guard must not evict `room_0`'s entry:
+        // its generation no longer matches what is actually stored.
+        drop(room_0_displaced);
+
+        assert_eq!(hotel.len(), 1);
+        assert_eq!(**room_0, 0);
+    }
+
+    #[test]
+    fn iter_yields_every_live_guard() {
+        let hotel = RodHashMap::<u32, u32>::new();
+
+        let _guards: Vec<_> = (0..8).map(|i| hotel.insert(i, i * 10)).collect();
+
+        let mut values: Vec<_> = hotel.iter().into_iter().map(|guard| **guard).collect();
+        values.sort_unstable();
+
+        assert_eq!(values, (0..8).map(|i| i * 10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn for_each_visits_every_live_entry() {
+        let hotel = RodHashMap::<u32, u32>::new();
+
+        let _guards: Vec<_> = (0..8).map(|i| hotel.insert(i, i * 10)).collect();
+
+        let mut seen = Vec::new();
+        hotel.for_each(|key, guard| seen.push((*key, ***guard)));
+        seen.sort_unstable();
+
+        assert_eq!(seen, (0..8).map(|i| (i, i * 10)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn retain_drops_the_map_side_of_non_matching_entries() {
+        let hotel = RodHashMap::<u32, u32>::new();
+
+        let guards: Vec<_> = (0..8).map(|i| hotel.insert(i, i)).collect();
+
+        hotel.retain(|key, _| key % 2 == 0);
+
+        assert_eq!(hotel.len(), 4);
+
+        drop(guards);
+
+        assert!(hotel.is_empty());
+    }
+
+    #[test]
+    fn weak_upgrades_while_the_entry_is_alive() {
+        let hotel = RodHashMap::<&str, u32>::new();
+
+        let room_0 = hotel.insert("Room Number 0", 0);
+        let weak = super::RodGuard::downgrade(&room_0);
+
+        let upgraded = weak.upgrade().unwrap();
+
+        assert_eq!(**upgraded, 0);
+    }
+
+    #[test]
+    fn weak_fails_to_upgrade_once_the_entry_is_gone() {
+        let hotel = RodHashMap::<&str, u32>::new();
+
+        let room_0 = hotel.insert("Room Number 0", 0);
+        let weak = super::RodGuard::downgrade(&room_0);
+
+        drop(room_0);
+
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn get_weak_does_not_keep_the_entry_alive() {
+        let hotel = RodHashMap::<&str, u32>::new();
+
+        let room_0 = hotel.insert("Room Number 0", 0);
+        let weak = hotel.get_weak(&"Room Number 0").unwrap();
+
+        drop(room_0);
+
+        assert!(hotel.is_empty());
+        assert!(weak.upgrade().is_none());
+    }
 }
@@ -0,0 +1,354 @@
+use std::{
+    borrow::Borrow,
+    collections::BTreeSet,
+    hash::{BuildHasher, Hash, RandomState},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock, RwLockReadGuard, RwLockWriteGuard, Weak,
+    },
+    thread::available_parallelism,
+};
+
+/// Number of shards to split the inner set into, sized so that disjoint keys
+/// hashing to different shards can be accessed without contending on the
+/// same lock.
+fn shard_count() -> usize {
+    let parallelism = available_parallelism().map_or(1, |n| n.get());
+
+    (parallelism * 4).next_power_of_two()
+}
+
+type Shards<K, V> = Arc<[RwLock<BTreeSet<RodEntry<K, V>>>]>;
+
+/// Index of the shard owning `hash`, taken from its high bits so that
+/// adjacent hashes still spread across shards.
+fn shard_index(shard_count: usize, hash: u64) -> usize {
+    (hash >> (u64::BITS - shard_count.trailing_zeros())) as usize
+}
+
+/// Like [`super::rod_btree_map::RodBTreeMap`], but each entry's value lives
+/// behind its own `RwLock` so callers can mutate a shared entry in place
+/// via [`RodGuard::get_mut`] instead of replacing the whole value.
+pub struct RodMutBTreeMap<K, V>
+where
+    K: Ord + Eq + Hash,
+{
+    shards: Shards<K, V>,
+    hasher: RandomState,
+    generation: AtomicU64,
+}
+
+impl<K: Ord + Eq + Hash, V> RodMutBTreeMap<K, V> {
+    pub fn new() -> Self {
+        let shards = (0..shard_count())
+            .map(|_| RwLock::new(BTreeSet::new()))
+            .collect::<Vec<_>>()
+            .into();
+
+        Self {
+            shards,
+            hasher: RandomState::new(),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    /// Next generation to tag a freshly inserted entry with, so a guard
+    /// whose `Drop` races a reinsertion under the same key can tell its
+    /// entry apart from the one that replaced it.
+    fn next_generation(&self) -> u64 {
+        self.generation.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.read().unwrap().len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Arc<RodGuard<K, V>> {
+        let hash = self.hasher.hash_one(&key);
+        let index = shard_index(self.shards.len(), hash);
+        let generation = self.next_generation();
+        let (entry, guard) = RodEntry::new(Arc::clone(&self.shards), hash, generation, key, value);
+        self.shards[index].write().unwrap().insert(entry);
+
+        guard
+    }
+
+    pub fn get(&self, key: &K) -> Option<Arc<RodGuard<K, V>>> {
+        let hash = self.hasher.hash_one(key);
+        let index = shard_index(self.shards.len(), hash);
+
+        self.shards[index]
+            .read()
+            .unwrap()
+            .get(key)
+            .map(|entry| entry.get())
+    }
+
+    /// Atomically looks up `key`, returning its guard if a live entry
+    /// exists, or calls `f` and inserts the result otherwise.
+    ///
+    /// Unlike a separate `get()` followed by `insert()`, this takes the
+    /// shard's write lock once for the whole operation, so two callers can
+    /// never both miss and insert a duplicate entry for the same key.
+    pub fn get_or_insert_with(&self, key: K, f: impl FnOnce() -> V) -> Arc<RodGuard<K, V>> {
+        let hash = self.hasher.hash_one(&key);
+        let index = shard_index(self.shards.len(), hash);
+        let mut shard = self.shards[index].write().unwrap();
+
+        if let Some(guard) = shard.get(&key).and_then(|entry| entry.value.upgrade()) {
+            return guard;
+        }
+
+        let generation = self.next_generation();
+        let (entry, guard) = RodEntry::new(Arc::clone(&self.shards), hash, generation, key, f());
+        shard.replace(entry);
+
+        guard
+    }
+}
+
+impl<K: Ord + Eq + Hash, V> Default for RodMutBTreeMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct RodEntry<K, V>
+where
+    K: Ord + Eq,
+{
+    key: Arc<K>,
+    generation: u64,
+    value: Weak<RodGuard<K, V>>,
+}
+
+impl<K: Ord + Eq + Hash, V> RodEntry<K, V> {
+    fn new(
+        shards: Shards<K, V>,
+        hash: u64,
+        generation: u64,
+        key: K,
+        value: V,
+    ) -> (Self, Arc<RodGuard<K, V>>) {
+        let key = Arc::new(key);
+        let guard = Arc::new(RodGuard::new(
+            shards,
+            hash,
+            generation,
+            Arc::clone(&key),
+            value,
+        ));
+
+        (
+            Self {
+                key,
+                generation,
+                value: Arc::downgrade(&guard),
+            },
+            guard,
+        )
+    }
+
+    fn get(&self) -> Arc<RodGuard<K, V>> {
+        self.value
+            .upgrade()
+            .expect("Value was dropped, this should NOT still be accessible")
+    }
+}
+
+impl<K: Ord + Eq, V> PartialEq for RodEntry<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key.eq(&other.key)
+    }
+}
+
+impl<K: Ord + Eq, V> Eq for RodEntry<K, V> {}
+
+impl<K: Ord + Eq, V> PartialOrd for RodEntry<K, V> {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Ord + Eq, V> Ord for RodEntry<K, V> {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.key.cmp(&other.key)
+    }
+}
+
+impl<K: Ord, V> Borrow<K> for RodEntry<K, V> {
+    fn borrow(&self) -> &K {
+        &self.key
+    }
+}
+
+/// A reference-counted, remove-on-drop handle whose value is locked
+/// independently of the map, so it can be mutated in place through any
+/// clone of the guard.
+///
+/// # Deadlock safety
+///
+/// This per-value lock is completely independent of the shard lock the map
+/// uses internally: acquiring it never blocks another key's `insert`/`get`,
+/// and dropping the last guard for this entry (which takes the shard lock)
+/// never blocks on it either. The one thing to avoid is holding a
+/// [`RodGuard::get_mut`]/[`RodGuard::read`] guard while trying to acquire
+/// *another* guard for the same entry on the same thread (e.g. calling
+/// `get_mut` or `read` on a clone returned by `map.get(key)`) — like any
+/// `RwLock`, that will deadlock a writer against itself. `map.get(key)`
+/// itself only takes the shard lock and does not block on the value lock.
+pub struct RodGuard<K, V>
+where
+    K: Ord,
+{
+    shards: Shards<K, V>,
+    hash: u64,
+    generation: u64,
+    key: Arc<K>,
+    value: RwLock<V>,
+}
+
+impl<K, V> RodGuard<K, V>
+where
+    K: Ord,
+{
+    fn new(
+        shards: Shards<K, V>,
+        hash: u64,
+        generation: u64,
+        key: Arc<K>,
+        value: V,
+    ) -> Self {
+        Self {
+            shards,
+            hash,
+            generation,
+            key,
+            value: RwLock::new(value),
+        }
+    }
+
+    /// Acquires this entry's value for shared, read-only access.
+    pub fn read(&self) -> RwLockReadGuard<'_, V> {
+        self.value.read().unwrap()
+    }
+
+    /// Acquires this entry's value for exclusive, mutable access.
+    pub fn get_mut(&self) -> RwLockWriteGuard<'_, V> {
+        self.value.write().unwrap()
+    }
+}
+
+impl<K, V> Drop for RodGuard<K, V>
+where
+    K: Ord,
+{
+    fn drop(&mut self) {
+        let index = shard_index(self.shards.len(), self.hash);
+        let mut shard = self.shards[index].write().unwrap();
+
+        // The entry for this key may have been removed and a new one
+        // reinserted since this guard's `Weak` expired; only remove it if
+        // it is still the entry we were created for.
+        if shard
+            .get(&*self.key)
+            .is_some_and(|entry| entry.generation == self.generation)
+        {
+            shard.remove(&*self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::RodMutBTreeMap;
+
+    #[test]
+    fn single_guard() {
+        let hotel = RodMutBTreeMap::<&str, u32>::new();
+
+        assert!(hotel.is_empty());
+
+        let room_0 = hotel.insert("Room Number 0", 0);
+
+        assert_eq!(hotel.len(), 1);
+
+        drop(room_0);
+
+        assert!(hotel.is_empty());
+    }
+
+    #[test]
+    fn get_mut_mutates_the_shared_entry() {
+        let hotel = RodMutBTreeMap::<&str, u32>::new();
+
+        let room_0 = hotel.insert("Room Number 0", 0);
+        let room_0_again = hotel.get(&"Room Number 0").unwrap();
+
+        *room_0.get_mut() += 1;
+
+        assert_eq!(*room_0_again.read(), 1);
+    }
+
+    #[test]
+    fn get_or_insert_with_returns_existing_guard() {
+        let hotel = RodMutBTreeMap::<&str, u32>::new();
+
+        let room_0 = hotel.insert("Room Number 0", 0);
+        let room_0_again = hotel.get_or_insert_with("Room Number 0", || panic!("must not run"));
+
+        assert!(Arc::ptr_eq(&room_0, &room_0_again));
+        assert_eq!(hotel.len(), 1);
+    }
+
+    #[test]
+    fn concurrent_inserts_of_disjoint_keys_succeed() {
+        let hotel = RodMutBTreeMap::<u32, u32>::new();
+
+        let guards = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..64)
+                .map(|i| {
+                    let hotel = &hotel;
+                    scope.spawn(move || hotel.insert(i, i))
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+
+        assert_eq!(hotel.len(), 64);
+
+        drop(guards);
+
+        assert!(hotel.is_empty());
+    }
+
+    #[test]
+    fn dropping_a_displaced_guard_does_not_evict_the_live_entry() {
+        let hotel = RodMutBTreeMap::<&str, u32>::new();
+
+        let room_0 = hotel.insert("Room Number 0", 0);
+        // `BTreeSet::insert` does not replace an equal element, so this
+        // second insert for the same key is silently discarded from the
+        // set while still handing back a live, orphaned guard.
+        let room_0_displaced = hotel.insert("Room Number 0", 1);
+
+        assert_eq!(hotel.len(), 1);
+
+        // Dropping the displaced guard must not evict `room_0`'s entry:
+        // its generation no longer matches what is actually stored.
+        drop(room_0_displaced);
+
+        assert_eq!(hotel.len(), 1);
+        assert_eq!(*room_0.read(), 0);
+    }
+}